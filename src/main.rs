@@ -1,3 +1,4 @@
+use std::env;
 use std::fs::{self, File};
 use std::process::Command;
 use std::path::{Path, PathBuf};
@@ -14,26 +15,63 @@ use rayon::prelude::*;
 use indicatif::{ProgressBar};
 use flate2::read::GzDecoder;
 use tar::Archive;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, USER_AGENT};
 
 const REPO_NAME: &str = "crates.io-index";
 
 const CARGO_TOML_CACHE: &str = "toml_cache";
 
+// Local mirror of the sparse registry protocol response bodies, sharded the
+// same way as index.crates.io itself.
+const SPARSE_INDEX_CACHE: &str = "sparse_index_cache";
+
 fn main() -> Result<()> {
-    download_git_index()?;
-    update_git_index()?;
+    let index = if use_git_index() {
+        download_git_index()?;
+        update_git_index()?;
+        parse_index(index_iterator().collect())?
+    } else {
+        fetch_sparse_index()?;
+        parse_index(sparse_cache_iterator().collect())?
+    };
 
-    check_and_download_crates(parse_index(index_iterator().collect())?)?;
+    check_and_download_crates(index)?;
 
     let fs_iterator: Vec<_> = cargo_toml_iterator().collect();
-    let macros = find_proc_macros(&fs_iterator)?;
+    let (macros, parse_errors) = find_proc_macros(&fs_iterator)?;
+    write_parse_errors(&parse_errors)?;
+
+    let ignored_keys: BTreeMap<String, Vec<String>> = macros
+        .iter()
+        .filter(|(_, entry)| !entry.ignored_paths.is_empty())
+        .map(|(name, entry)| (name.clone(), entry.ignored_paths.clone()))
+        .collect();
+    write_ignored_manifest_keys(&ignored_keys)?;
+
+    let locks = if extract_lockfiles() { read_cargo_locks()? } else { BTreeMap::new() };
+    write_transitive_stats(&macros, &locks)?;
+
+    let macros: BTreeMap<String, CargoToml> = macros
+        .into_iter()
+        .map(|(name, entry)| (name, entry.toml))
+        .collect();
 
-    let weird_deps = find_weird_dependencies(macros);
+    let classification = load_classification()?;
+    let classified_deps = classify_dependencies(macros, &classification);
 
-    write_data(weird_deps)?;
+    write_data(classified_deps)?;
     Ok(())
 }
 
+/// Whether to fall back to the full `crates.io-index` git checkout instead of
+/// the sparse HTTP index. Useful when working offline against an existing
+/// checkout, since the index then comes from the checkout rather than the
+/// crates.io API and index.crates.io. Crate tarballs not yet in the
+/// `toml_cache` are still downloaded from static.crates.io either way.
+fn use_git_index() -> bool {
+    env::args().any(|arg| arg == "--git-index")
+}
+
 fn download_git_index() -> Result<()> {
     println!("Checking git index...");
     if fs::metadata(format!("{}/{}", REPO_NAME, ".git")).is_err() {
@@ -69,6 +107,268 @@ fn update_git_index() -> Result<()> {
         .map(|_| ())
 }
 
+/// Syncs the local sparse index cache with crates.io: lists every crate name
+/// via the crates.io API, then fetches/revalidates each crate's index file
+/// over the sparse registry protocol, skipping anything that's unchanged.
+fn fetch_sparse_index() -> Result<()> {
+    println!("Listing crates from crates.io API...");
+    let mut pending = list_crate_names()?;
+    println!("Syncing sparse index cache for {} crates...", pending.len());
+
+    let client = reqwest::blocking::Client::new();
+    let progress_bar = ProgressBar::new(pending.len() as u64);
+    // Crate name -> reason for its most recent retryable failure, so that a
+    // crate which never recovers still has something to show for it once
+    // retries run out, instead of just a bare name.
+    let mut last_reason: BTreeMap<String, String> = BTreeMap::new();
+    let mut failures = Vec::new();
+
+    // Retry rate-limited/server-error crates in their own round, backing off
+    // once between rounds rather than blocking a rayon worker thread per
+    // failed request — otherwise a throttled batch would put most of the
+    // pool to sleep and stall unrelated, otherwise-successful requests.
+    for attempt in 0..=MAX_SPARSE_FETCH_RETRIES {
+        if pending.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            std::thread::sleep(std::time::Duration::from_secs(1 << (attempt - 1)));
+        }
+
+        let retryable = std::sync::Mutex::new(Vec::new());
+        let round_failures = std::sync::Mutex::new(Vec::new());
+        pending.into_par_iter().for_each(|name| match sync_sparse_index_entry(&client, &name) {
+            Ok(SyncOutcome::Synced) => progress_bar.inc(1),
+            Ok(SyncOutcome::Retryable(reason)) => retryable.lock().unwrap().push((name, reason)),
+            Err(e) => {
+                progress_bar.println(format!("Failed to sync sparse index entry for {}: {}", name, e));
+                round_failures.lock().unwrap().push(name);
+                progress_bar.inc(1);
+            }
+        });
+
+        failures.extend(round_failures.into_inner().unwrap());
+        let retryable = retryable.into_inner().unwrap();
+        for (name, reason) in &retryable {
+            last_reason.insert(name.clone(), reason.clone());
+        }
+        pending = retryable.into_iter().map(|(name, _)| name).collect();
+    }
+
+    if !pending.is_empty() {
+        println!("Giving up on {} crates after {} retries.", pending.len(), MAX_SPARSE_FETCH_RETRIES);
+        for name in &pending {
+            let reason = last_reason.get(name).map(String::as_str).unwrap_or("unknown reason");
+            progress_bar.println(format!("Giving up on sparse index entry for {}: {}", name, reason));
+        }
+        progress_bar.inc(pending.len() as u64);
+        failures.extend(pending);
+    }
+    progress_bar.finish();
+
+    if !failures.is_empty() {
+        println!("Failed to sync {} crates; see sparse_index_errors.", failures.len());
+        let failures = serde_json::to_string(&failures)?;
+        File::create("sparse_index_errors")?.write_all(failures.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CratesResponse {
+    crates: Vec<CratesResponseEntry>,
+    meta: CratesResponseMeta,
+}
+
+#[derive(Deserialize)]
+struct CratesResponseEntry {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct CratesResponseMeta {
+    next_page: Option<String>,
+}
+
+/// How many times `fetch_crate_list_page` will retry a single page of the
+/// crates.io listing before giving up.
+const MAX_CRATE_LIST_RETRIES: u32 = 5;
+
+/// Crawls the full crates.io crate list via the API's seek-based cursor
+/// (`meta.next_page`), requesting `sort=new` so the listing is ordered by
+/// crate id and the API serves it through that cursor instead of `page`/
+/// `per_page` offsets. Offset pagination is rejected with a 400 once the
+/// offset passes 10,000 results, so `list_crate_names` bails loudly if a
+/// `next_page` ever comes back without a `seek=` cursor rather than quietly
+/// truncating the crawl partway through the ~150k-crate registry.
+fn list_crate_names() -> Result<Vec<String>> {
+    let client = reqwest::blocking::Client::new();
+    let mut names = Vec::new();
+    let mut next_page = Some("?per_page=100&sort=new".to_string());
+
+    while let Some(page) = next_page {
+        // `meta.next_page` is a bare query string (no `/api/v1/crates`
+        // prefix), just like the hardcoded first request above, so the route
+        // is prepended; a value that already starts with `/` is taken as a
+        // full path and only gets the host.
+        let url = if page.starts_with('/') {
+            format!("https://crates.io{}", page)
+        } else {
+            format!("https://crates.io/api/v1/crates{}", page)
+        };
+
+        let response = fetch_crate_list_page(&client, &url)?;
+
+        names.extend(response.crates.into_iter().map(|entry| entry.name));
+        next_page = response.meta.next_page;
+
+        if let Some(next) = &next_page {
+            if !next.contains("seek=") {
+                anyhow::bail!(
+                    "crates.io crate list returned non-seek pagination ({:?}); offset pagination caps at 10,000 results and would silently truncate the crawl",
+                    next
+                );
+            }
+
+            // crates.io's crawler policy caps non-download traffic at
+            // roughly 1 request/sec; stay under that even when every page
+            // succeeds on the first try.
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+    }
+
+    Ok(names)
+}
+
+/// Fetches a single page of the crates.io crate listing, retrying with
+/// backoff on `429`/`5xx`. Unlike `fetch_sparse_index`'s retry rounds, this
+/// loop is sequential rather than parallel, so sleeping the calling thread
+/// between attempts doesn't tie up anything else.
+fn fetch_crate_list_page(client: &reqwest::blocking::Client, url: &str) -> Result<CratesResponse> {
+    for attempt in 0..=MAX_CRATE_LIST_RETRIES {
+        if attempt > 0 {
+            std::thread::sleep(std::time::Duration::from_secs(1 << attempt.min(6)));
+        }
+
+        let response = client
+            .get(url)
+            .header(USER_AGENT, "proc-macro-stats (https://github.com/edward-shen/proc-macro-stats)")
+            .send()
+            .context("Failed to list crates from crates.io")?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            continue;
+        }
+
+        return response
+            .error_for_status()
+            .context("crates.io crate list request returned an error status")?
+            .json()
+            .context("Failed to parse crates.io crate list");
+    }
+
+    anyhow::bail!("crates.io crate list request to {} failed after {} retries", url, MAX_CRATE_LIST_RETRIES);
+}
+
+/// Mirrors the sharding scheme used by index.crates.io: 1 and 2 character
+/// names get their own top-level directory, 3 character names are split by
+/// their first character, and everything else is split two-two.
+///
+/// Unlike `get_cache_name` (which shards the `toml_cache` and leaves casing
+/// alone), this lowercases the name first: the real sparse index shards by
+/// lowercased name, so the two caches are intentionally inconsistent here.
+fn sparse_index_shard(crate_name: &str) -> String {
+    let lower = crate_name.to_lowercase();
+    match lower.len() {
+        1 => format!("1/{}", lower),
+        2 => format!("2/{}", lower),
+        3 => format!("3/{}/{}", &lower[..1], lower),
+        _ => format!("{}/{}/{}", &lower[..2], &lower[2..4], lower),
+    }
+}
+
+/// How many extra rounds `fetch_sparse_index` will retry rate-limited or
+/// server-error crates before giving up and recording them as failures.
+const MAX_SPARSE_FETCH_RETRIES: u32 = 3;
+
+enum SyncOutcome {
+    Synced,
+    /// Hit a `429`/`5xx` or couldn't connect; worth retrying in a later
+    /// round. Carries why, so a crate that's still failing once retries are
+    /// exhausted can still be diagnosed.
+    Retryable(String),
+}
+
+/// Fetches a single crate's sparse index file, sending along the cached
+/// `ETag`/`Last-Modified` as conditional headers so an unchanged crate costs
+/// only a `304`. Makes a single attempt — `fetch_sparse_index` is
+/// responsible for retrying `SyncOutcome::Retryable` crates, so a throttled
+/// batch doesn't tie up rayon worker threads sleeping one request at a time.
+fn sync_sparse_index_entry(client: &reqwest::blocking::Client, crate_name: &str) -> Result<SyncOutcome> {
+    let shard = sparse_index_shard(crate_name);
+    let cache_path = Path::new(SPARSE_INDEX_CACHE).join(&shard);
+    let meta_path = cache_path.with_extension("meta");
+
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut request = client
+        .get(format!("https://index.crates.io/{}", shard))
+        .header(USER_AGENT, "proc-macro-stats (https://github.com/edward-shen/proc-macro-stats)");
+
+    if let Ok(meta) = fs::read_to_string(&meta_path) {
+        let mut lines = meta.lines();
+        if let Some(etag) = lines.next().filter(|line| !line.is_empty()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = lines.next().filter(|line| !line.is_empty()) {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(e) => return Ok(SyncOutcome::Retryable(e.to_string())),
+    };
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_MODIFIED || status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(SyncOutcome::Synced);
+    }
+
+    if status.is_success() {
+        let etag = response.headers().get(ETAG).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+        let last_modified = response.headers().get(LAST_MODIFIED).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+        let body = response.bytes().context("Failed to read sparse index entry")?;
+
+        fs::write(&cache_path, &body)?;
+        fs::write(&meta_path, format!("{}\n{}\n", etag, last_modified))?;
+        return Ok(SyncOutcome::Synced);
+    }
+
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        return Ok(SyncOutcome::Retryable(format!("HTTP {}", status)));
+    }
+
+    anyhow::bail!("sparse index request for {} failed with {}", crate_name, status);
+}
+
+fn sparse_cache_iterator() -> impl Iterator<Item = DirEntry> {
+    WalkDir::new(SPARSE_INDEX_CACHE)
+        .into_iter()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if entry.file_type().is_file() && entry.path().extension() != Some(OsStr::new("meta")) {
+                Some(entry)
+            } else {
+                None
+            }
+        })
+}
+
 fn index_iterator() -> impl Iterator<Item = DirEntry> {
     WalkDir::new(REPO_NAME)
         .into_iter()
@@ -128,21 +428,36 @@ struct GitIndexEntry {
     yanked: bool,
 }
 
+/// Whether to also extract an embedded `Cargo.lock` (when the tarball has
+/// one) alongside each crate's `Cargo.toml`, for the transitive dependency
+/// survey.
+fn extract_lockfiles() -> bool {
+    env::args().any(|arg| arg == "--with-lockfile")
+}
+
 fn check_and_download_crates(index: Vec<GitIndexEntry>) -> Result<()> {
     println!("Checking and downloading crate manifest files...");
+    let with_lockfile = extract_lockfiles();
     let progress_bar = ProgressBar::new(index.len() as u64);
     index
         .into_par_iter()
         .map(|GitIndexEntry { name, vers, .. }| {
             let path = get_cache_name(&name)?;
 
-            let toml_path = {
+            let crate_dir = {
                 let mut path = path.clone();
                 path.push(format!("{}-{}", name, vers));
-                path.push("Cargo.toml");
                 path
             };
-            if toml_path.exists() {
+            let toml_path = crate_dir.join("Cargo.toml");
+            let lock_path = crate_dir.join("Cargo.lock");
+            // Most proc-macros are libraries and ship no Cargo.lock in their
+            // tarball at all, so `lock_path` alone can never be used to tell
+            // "checked, has none" apart from "never checked" — without this
+            // marker every lockless crate would redownload its full tarball
+            // on every run.
+            let lock_absent_marker = lock_path.with_extension("absent");
+            if toml_path.exists() && (!with_lockfile || lock_path.exists() || lock_absent_marker.exists()) {
                 // println!("Skipping {} {}", name, vers);
                 progress_bar.inc(1);
                 return Ok(());
@@ -158,17 +473,28 @@ fn check_and_download_crates(index: Vec<GitIndexEntry>) -> Result<()> {
                 .bytes()
                 .context("Failed to read crate")?;
             let mut archive = Archive::new(GzDecoder::new(tarball.as_ref()));
+            let mut found_toml = false;
+            let mut found_lock = false;
             for entry in archive.entries()? {
+                if found_toml && (!with_lockfile || found_lock) {
+                    break;
+                }
                 if let Ok(mut entry) = entry {
                     if let Some(file_name) = entry.path()?.file_name()  {
                         if file_name == OsStr::new("Cargo.toml") {
-                            entry.unpack_in(path)?;
-                            progress_bar.inc(1);
-                            break;
+                            entry.unpack_in(&path)?;
+                            found_toml = true;
+                        } else if with_lockfile && file_name == OsStr::new("Cargo.lock") {
+                            entry.unpack_in(&path)?;
+                            found_lock = true;
                         }
                     }
                 }
             }
+            if with_lockfile && !found_lock {
+                fs::write(&lock_absent_marker, "")?;
+            }
+            progress_bar.inc(1);
             Ok(())
         }).collect::<Result<Vec<_>>>()?;
 
@@ -199,7 +525,20 @@ fn cargo_toml_iterator() -> impl Iterator<Item = DirEntry> {
         .into_iter()
         .filter_map(|entry| {
             let entry = entry.ok()?;
-            if entry.file_type().is_file() {
+            if entry.file_type().is_file() && entry.file_name() == OsStr::new("Cargo.toml") {
+                Some(entry)
+            } else {
+                None
+            }
+        })
+}
+
+fn cargo_lock_iterator() -> impl Iterator<Item = DirEntry> {
+    WalkDir::new(CARGO_TOML_CACHE)
+        .into_iter()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if entry.file_type().is_file() && entry.file_name() == OsStr::new("Cargo.lock") {
                 Some(entry)
             } else {
                 None
@@ -213,6 +552,45 @@ struct CargoToml {
     package: TomlPackage,
     lib: Option<TomlLib>,
     dependencies: Option<BTreeMap<String, toml::Value>>,
+    #[serde(rename = "build-dependencies")]
+    build_dependencies: Option<BTreeMap<String, toml::Value>>,
+    #[serde(rename = "dev-dependencies")]
+    dev_dependencies: Option<BTreeMap<String, toml::Value>>,
+    target: Option<BTreeMap<String, TomlTarget>>,
+}
+
+impl CargoToml {
+    /// Every dependency table in this manifest, tagged with the table it
+    /// came from (`dependencies`, `build-dependencies`, `dev-dependencies`,
+    /// or `target.<cfg>.<table>` for platform-specific ones).
+    fn dependency_tables(&self) -> Vec<(String, &BTreeMap<String, toml::Value>)> {
+        let mut tables = Vec::new();
+
+        if let Some(deps) = &self.dependencies {
+            tables.push(("dependencies".to_string(), deps));
+        }
+        if let Some(deps) = &self.build_dependencies {
+            tables.push(("build-dependencies".to_string(), deps));
+        }
+        if let Some(deps) = &self.dev_dependencies {
+            tables.push(("dev-dependencies".to_string(), deps));
+        }
+        if let Some(targets) = &self.target {
+            for (cfg, target) in targets {
+                if let Some(deps) = &target.dependencies {
+                    tables.push((format!("target.{}.dependencies", cfg), deps));
+                }
+                if let Some(deps) = &target.build_dependencies {
+                    tables.push((format!("target.{}.build-dependencies", cfg), deps));
+                }
+                if let Some(deps) = &target.dev_dependencies {
+                    tables.push((format!("target.{}.dev-dependencies", cfg), deps));
+                }
+            }
+        }
+
+        tables
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -220,6 +598,15 @@ struct TomlPackage {
     name: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct TomlTarget {
+    dependencies: Option<BTreeMap<String, toml::Value>>,
+    #[serde(rename = "build-dependencies")]
+    build_dependencies: Option<BTreeMap<String, toml::Value>>,
+    #[serde(rename = "dev-dependencies")]
+    dev_dependencies: Option<BTreeMap<String, toml::Value>>,
+}
+
 
 #[derive(Deserialize, Debug, Default)]
 struct TomlLib {
@@ -228,22 +615,119 @@ struct TomlLib {
 }
 
 
-fn find_proc_macros(cargo_toml_files: &[DirEntry]) -> Result<BTreeMap<String, CargoToml>> {
+/// A parsed proc-macro manifest, along with every TOML path our `CargoToml`
+/// model doesn't account for (custom `[package.metadata.*]` tables, unusual
+/// `[lib]` keys, etc).
+struct ProcMacroEntry {
+    toml: CargoToml,
+    ignored_paths: Vec<String>,
+    /// The `{name}-{version}` directory this manifest was cached under, used
+    /// to locate its sibling `Cargo.lock` for the transitive dependency
+    /// survey.
+    crate_id: String,
+}
+
+/// Whether a manifest failed to parse because it's genuinely malformed TOML,
+/// or because it's valid TOML whose values don't fit the types our structs
+/// expect (e.g. `name.workspace = true`, or a `[dependencies]` that isn't a
+/// table). Keys our structs don't model at all are not errors; they are
+/// collected as ignored paths instead.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ParseErrorKind {
+    Malformed,
+    UnsupportedSchema,
+}
+
+#[derive(Serialize, Debug)]
+struct ParseErrorRecord {
+    crate_id: String,
+    kind: ParseErrorKind,
+    line: Option<usize>,
+    column: Option<usize>,
+    snippet: Option<String>,
+    message: String,
+}
+
+/// The `{name}-{version}` directory `check_and_download_crates` unpacked the
+/// manifest into, used to identify a crate when we can't trust its parsed
+/// contents.
+fn crate_id_from_cache_path(path: &Path) -> String {
+    path.parent()
+        .and_then(|parent| parent.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned())
+}
+
+fn line_col(source: &str, byte_offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut column = 1;
+    for (i, ch) in source.char_indices() {
+        if i >= byte_offset {
+            break;
+        }
+        if ch == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    (line, column)
+}
+
+fn build_parse_error_record(crate_id: String, toml_raw: &str, error: toml::de::Error) -> ParseErrorRecord {
+    let kind = if toml_raw.parse::<toml::Value>().is_err() {
+        ParseErrorKind::Malformed
+    } else {
+        ParseErrorKind::UnsupportedSchema
+    };
+
+    let position = error.span().map(|span| line_col(toml_raw, span.start));
+    let snippet = position.and_then(|(line, _)| toml_raw.lines().nth(line - 1)).map(|line| line.trim().to_string());
+
+    ParseErrorRecord {
+        crate_id,
+        kind,
+        line: position.map(|(line, _)| line),
+        column: position.map(|(_, column)| column),
+        snippet,
+        // `Error::message()` isn't part of the stable `toml::de::Error`
+        // API (only `span()` and `Display` are guaranteed); `to_string()`
+        // is slightly noisier (it repeats the position we already record
+        // separately) but won't silently break if `toml` is upgraded.
+        message: error.to_string(),
+    }
+}
+
+fn find_proc_macros(cargo_toml_files: &[DirEntry]) -> Result<(BTreeMap<String, ProcMacroEntry>, Vec<ParseErrorRecord>)> {
     println!("Finding proc macros...");
     let progress_bar = ProgressBar::new(cargo_toml_files.len() as u64);
-    let map: BTreeMap<String, CargoToml> = cargo_toml_files.par_iter().map(|entry| {
+    let parse_errors = std::sync::Mutex::new(Vec::new());
+
+    let map: BTreeMap<String, ProcMacroEntry> = cargo_toml_files.par_iter().map(|entry| {
         progress_bar.inc(1);
         let toml_raw = fs::read_to_string(entry.path())?;
-        let toml: CargoToml = match toml::from_str(&toml_raw) {
+
+        let mut ignored_paths = Vec::new();
+        let deserializer = toml::Deserializer::new(&toml_raw);
+        let toml: CargoToml = match serde_ignored::deserialize(deserializer, |path| {
+            ignored_paths.push(path.to_string());
+        }) {
             Ok(toml) => toml,
             Err(e) => {
-                progress_bar.println(format!("Got invalid manifest file at {}: {}", entry.path().to_string_lossy(), e));
+                let record = build_parse_error_record(crate_id_from_cache_path(entry.path()), &toml_raw, e);
+                progress_bar.println(format!("Got invalid manifest file at {}: {}", entry.path().to_string_lossy(), record.message));
+                parse_errors.lock().unwrap().push(record);
                 return Ok(None);
             }
         };
 
         if toml.lib.as_ref().map(|v| v.proc_macro).flatten().unwrap_or_default() {
-            Ok(Some((toml.package.name.clone(), toml)))
+            ignored_paths.sort_unstable();
+            ignored_paths.dedup();
+            let crate_id = crate_id_from_cache_path(entry.path());
+            Ok(Some((toml.package.name.clone(), ProcMacroEntry { toml, ignored_paths, crate_id })))
         } else {
             Ok(None)
         }
@@ -255,77 +739,302 @@ fn find_proc_macros(cargo_toml_files: &[DirEntry]) -> Result<BTreeMap<String, Ca
 
     progress_bar.finish();
 
+    let parse_errors = parse_errors.into_inner().unwrap();
+    let malformed = parse_errors.iter().filter(|e| e.kind == ParseErrorKind::Malformed).count();
+    let unsupported_schema = parse_errors.len() - malformed;
+
     println!("Found {} proc macros.", map.len());
+    println!(
+        "Skipped {} manifests ({} malformed, {} using schema we don't model).",
+        parse_errors.len(),
+        malformed,
+        unsupported_schema
+    );
+
+    Ok((map, parse_errors))
+}
+
+fn write_parse_errors(parse_errors: &[ParseErrorRecord]) -> Result<()> {
+    let parse_errors = serde_json::to_string(parse_errors)?;
+    File::create("parse_errors")?.write_all(parse_errors.as_bytes())?;
+    Ok(())
+}
+
+/// Counts, across every proc-macro, how many of them use each manifest key
+/// our `CargoToml` model doesn't otherwise capture.
+fn write_ignored_manifest_keys(ignored_keys: &BTreeMap<String, Vec<String>>) -> Result<()> {
+    let stats = ignored_keys.values().flatten().fold(BTreeMap::new(), |mut acc, path| {
+        *acc.entry(path.clone()).or_insert(0usize) += 1;
+        acc
+    });
+
+    println!(
+        "Found {} proc macros with non-standard manifest keys, across {} distinct keys.",
+        ignored_keys.len(),
+        stats.len()
+    );
+
+    let stats = serde_json::to_string(&stats)?;
+    File::create("manifest_key_stats")?.write_all(stats.as_bytes())?;
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct CargoLock {
+    package: Vec<LockPackage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LockPackage {
+    name: String,
+    version: String,
+    /// `None` for path/workspace-local packages; `Some("registry+...")` or
+    /// `Some("git+...")` otherwise. Used to flag dependencies pulled from
+    /// somewhere other than crates.io.
+    source: Option<String>,
+}
+
+/// The `source` crates.io itself resolves to in `Cargo.lock`; anything else
+/// (a git dependency, a path dependency with no source at all, or another
+/// registry) is worth flagging separately.
+const CRATES_IO_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// Crates that, by themselves, make up a meaningful chunk of a dependency
+/// closure's compile time.
+const ASYNC_RUNTIMES: &[&str] = &["tokio", "async-std", "smol", "actix-rt"];
+
+fn read_cargo_locks() -> Result<BTreeMap<String, CargoLock>> {
+    println!("Reading embedded Cargo.lock files...");
+    let map: BTreeMap<String, CargoLock> = cargo_lock_iterator()
+        .par_bridge()
+        .filter_map(|entry| {
+            let crate_id = crate_id_from_cache_path(entry.path());
+            let raw = fs::read_to_string(entry.path()).ok()?;
+            let lock: CargoLock = toml::from_str(&raw).ok()?;
+            Some((crate_id, lock))
+        })
+        .collect();
+    println!("Parsed {} embedded Cargo.lock files.", map.len());
     Ok(map)
 }
 
-const NORMAL_DEPS: &[&str] = &[
-    "syn",
-    "proc-macro2",
-    "quote",
-    "proc-macro-error",
-    "proc-macro-crate",
-    "proc-macro-hack",
-    "darling",
-    "heck",
-    "lazy_static",
-    "regex",
-    "Inflector",
-    "anyhow",
-    "convert_case",
-    "itertools",
-    "once_cell",
-    "rand", // ????
-    "synstructure",
-    "unicode-xid",
-    "failure",
-];
-
-fn find_weird_dependencies(mapping: BTreeMap<String, CargoToml>) -> BTreeMap<String, CargoToml>{
-    println!("Finding weird dependencies...");
+#[derive(Serialize, Debug)]
+struct TransitiveReport {
+    name: String,
+    /// Every resolved package, as `name@version` — distinct versions of the
+    /// same crate are kept apart rather than collapsed to a name set.
+    transitive_crates: Vec<String>,
+    heavy_roots: Vec<String>,
+    /// Resolved packages whose `source` isn't crates.io (git dependencies,
+    /// path dependencies, or another registry), as `name@version (source)`.
+    non_crates_io_sources: Vec<String>,
+}
+
+/// Summarizes a crate's resolved dependency graph: the full set of crates it
+/// pulls in transitively, any "heavy" roots within it (multiple versions of
+/// the same crate, or a whole async runtime), and any dependency that didn't
+/// come from crates.io.
+///
+/// `own_name` excludes the analyzed crate's own entry from the lock — Cargo
+/// always records the root package alongside its dependencies, and with no
+/// `source` of its own it would otherwise be misreported as a path
+/// dependency of itself. Matched on name *and* version (from `crate_id`,
+/// which `check_and_download_crates` names `{name}-{version}`) rather than
+/// name alone, so a genuine transitive dependency that happens to share the
+/// root's name (a different version of itself) isn't dropped too.
+fn analyze_transitive_dependencies(crate_id: &str, own_name: &str, lock: &CargoLock) -> TransitiveReport {
+    let own_version = crate_id.strip_prefix(&format!("{}-", own_name));
+    let deps: Vec<&LockPackage> = lock
+        .package
+        .iter()
+        .filter(|package| !(package.name == own_name && Some(package.version.as_str()) == own_version))
+        .collect();
+
+    let mut version_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for package in &deps {
+        *version_counts.entry(package.name.as_str()).or_insert(0) += 1;
+    }
+
+    let mut heavy_roots = Vec::new();
+    for (name, count) in &version_counts {
+        if *count > 1 {
+            heavy_roots.push(format!("{} ({} versions)", name, count));
+        }
+        if ASYNC_RUNTIMES.contains(name) {
+            heavy_roots.push(format!("{} (async runtime)", name));
+        }
+    }
+
+    let non_crates_io_sources = deps
+        .iter()
+        .filter(|package| package.source.as_deref() != Some(CRATES_IO_SOURCE))
+        .map(|package| {
+            format!(
+                "{}@{} ({})",
+                package.name,
+                package.version,
+                package.source.as_deref().unwrap_or("path dependency")
+            )
+        })
+        .collect();
+
+    TransitiveReport {
+        name: crate_id.to_string(),
+        transitive_crates: deps.iter().map(|p| format!("{}@{}", p.name, p.version)).collect(),
+        heavy_roots,
+        non_crates_io_sources,
+    }
+}
+
+fn write_transitive_stats(macros: &BTreeMap<String, ProcMacroEntry>, locks: &BTreeMap<String, CargoLock>) -> Result<()> {
+    let reports: Vec<TransitiveReport> = macros
+        .values()
+        .filter_map(|entry| locks.get(&entry.crate_id).map(|lock| analyze_transitive_dependencies(&entry.crate_id, &entry.toml.package.name, lock)))
+        .collect();
+
+    println!(
+        "Found embedded Cargo.lock files for {} of {} proc macros; {} have a heavy dependency root, {} depend on something outside crates.io.",
+        reports.len(),
+        macros.len(),
+        reports.iter().filter(|r| !r.heavy_roots.is_empty()).count(),
+        reports.iter().filter(|r| !r.non_crates_io_sources.is_empty()).count()
+    );
+
+    let reports = serde_json::to_string(&reports)?;
+    File::create("transitive_stats")?.write_all(reports.as_bytes())?;
+
+    Ok(())
+}
+
+/// Maps a dependency name to the category a user's classification file says
+/// it belongs to (e.g. `parsing`, `codegen`, `error-handling`). Anything not
+/// present in the map is left for `classify_dependencies` to bucket as
+/// `"other"`.
+type Classification = BTreeMap<String, String>;
+
+/// The built-in classification, covering the dependencies that show up
+/// across most proc-macro crates. Users can override this entirely by
+/// passing `--classification <file>` with their own TOML or JSON mapping of
+/// dependency name to category.
+fn default_classification() -> Classification {
+    [
+        ("syn", "parsing"),
+        ("proc-macro2", "codegen"),
+        ("quote", "codegen"),
+        ("proc-macro-error", "error-handling"),
+        ("proc-macro-crate", "codegen"),
+        ("proc-macro-hack", "codegen"),
+        ("darling", "parsing"),
+        ("heck", "string-case"),
+        ("lazy_static", "runtime"),
+        ("regex", "parsing"),
+        ("Inflector", "string-case"),
+        ("anyhow", "error-handling"),
+        ("convert_case", "string-case"),
+        ("itertools", "utility"),
+        ("once_cell", "runtime"),
+        ("rand", "runtime"), // ????
+        ("synstructure", "parsing"),
+        ("unicode-xid", "parsing"),
+        ("failure", "error-handling"),
+    ]
+    .into_iter()
+    .map(|(name, category)| (name.to_string(), category.to_string()))
+    .collect()
+}
+
+fn classification_path() -> Option<PathBuf> {
+    let mut args = env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--classification" {
+            return args.next().map(PathBuf::from);
+        }
+    }
+    None
+}
+
+fn load_classification() -> Result<Classification> {
+    let path = match classification_path() {
+        Some(path) => path,
+        None => return Ok(default_classification()),
+    };
+
+    println!("Loading dependency classification from {}...", path.display());
+    let raw = fs::read_to_string(&path).with_context(|| format!("Failed to read classification file at {}", path.display()))?;
+
+    if path.extension().and_then(OsStr::to_str) == Some("json") {
+        serde_json::from_str(&raw).context("Failed to parse classification file as JSON")
+    } else {
+        toml::from_str(&raw).context("Failed to parse classification file as TOML")
+    }
+}
+
+/// A dependency, tagged with the manifest table it was declared in (e.g.
+/// `dependencies`, `build-dependencies`, or
+/// `target.cfg(unix).dev-dependencies`) and the category the classification
+/// assigns it, or `"other"` if unclassified.
+#[derive(Serialize, Debug, Clone)]
+struct TaggedDependency {
+    name: String,
+    table: String,
+    category: String,
+}
+
+fn classify_dependencies(mapping: BTreeMap<String, CargoToml>, classification: &Classification) -> BTreeMap<String, Vec<TaggedDependency>> {
+    println!("Classifying dependencies...");
     let res: BTreeMap<_, _> = mapping
         .into_par_iter()
-        .filter_map(|(cargo_name, mut toml)| {
-            let deps = toml.dependencies.as_mut()?;
-
-            for dep in NORMAL_DEPS {
-                deps.remove(&dep.to_string());
-            }
+        .filter_map(|(cargo_name, toml)| {
+            let deps: Vec<TaggedDependency> = toml
+                .dependency_tables()
+                .into_iter()
+                .flat_map(|(table, deps)| {
+                    deps.keys()
+                        .map(|name| TaggedDependency {
+                            name: name.clone(),
+                            table: table.clone(),
+                            category: classification.get(name).cloned().unwrap_or_else(|| "other".to_string()),
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
 
-            if deps.is_empty() {
-                None
+            if deps.iter().any(|dep| dep.category == "other") {
+                Some((cargo_name, deps))
             } else {
-                Some((cargo_name, toml))
+                None
             }
         })
         .collect();
-    println!("Found {} proc macros with non-standard dependencies.", res.len());
+    println!("Found {} proc macros with at least one uncategorized dependency.", res.len());
     res
 }
 
-fn write_data(data: BTreeMap<String, CargoToml>) -> Result<()> {
+fn write_data(data: BTreeMap<String, Vec<TaggedDependency>>) -> Result<()> {
     #[derive(Serialize)]
     struct Data {
         name: String,
-        deps: Vec<String>,
+        deps: Vec<TaggedDependency>,
     }
 
     let stats = dashmap::DashMap::new();
+    let category_stats = dashmap::DashMap::new();
 
     let data = data.into_par_iter().filter_map(|(key, value)| {
-        if value.dependencies.as_ref().map(|deps| deps.len()).unwrap_or_default() > 1 {
+        if value.len() > 1 {
             Some((key, value))
         } else {
             None
         }
     }).collect::<BTreeMap<_, _>>();
 
-    println!("Found {} proc macro crates with > 1 dependency, excluding 'standard' dependencies", data.len());
+    println!("Found {} proc macro crates with > 1 tracked dependency", data.len());
 
-    let dependencies: Vec<_> = data.into_par_iter().map(|(name, value)| {
-        let deps: Vec<_> = value.dependencies.unwrap().into_keys().collect();
+    let dependencies: Vec<_> = data.into_par_iter().map(|(name, deps)| {
         for dep in &deps {
-            stats.entry(dep.clone()).or_insert(AtomicUsize::new(0)).fetch_add(1, Ordering::Relaxed);
+            stats.entry(dep.name.clone()).or_insert(AtomicUsize::new(0)).fetch_add(1, Ordering::Relaxed);
+            category_stats.entry(dep.category.clone()).or_insert(AtomicUsize::new(0)).fetch_add(1, Ordering::Relaxed);
         }
         Data {
             name,
@@ -335,9 +1044,12 @@ fn write_data(data: BTreeMap<String, CargoToml>) -> Result<()> {
     let data = serde_json::to_string(&dependencies)?;
     File::create("data")?.write_all(data.as_bytes())?;
 
-    println!("Found {} non-standard dependencies", stats.len());
+    println!("Found {} distinct dependencies across {} categories", stats.len(), category_stats.len());
     let stats = serde_json::to_string(&stats)?;
     File::create("stats")?.write_all(stats.as_bytes())?;
 
+    let category_stats = serde_json::to_string(&category_stats)?;
+    File::create("category_stats")?.write_all(category_stats.as_bytes())?;
+
     Ok(())
 }
\ No newline at end of file